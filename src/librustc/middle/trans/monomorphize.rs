@@ -10,7 +10,7 @@
 
 use back::link::exported_name;
 use driver::session;
-use lib::llvm::ValueRef;
+use lib::llvm::{ValueRef, structural_hash};
 use middle::trans::base::{set_llvm_fn_attrs, set_inline_hint};
 use middle::trans::base::{trans_enum_variant, push_ctxt, get_item_val};
 use middle::trans::base::{trans_fn, decl_internal_rust_fn};
@@ -24,8 +24,17 @@ use util::ppaux::Repr;
 use syntax::abi;
 use syntax::ast;
 use syntax::ast_map;
+use syntax::ast_util;
 use syntax::ast_util::local_def;
+use syntax::visit;
+use syntax::visit::Visitor;
 use std::hash::{sip, Hash};
+use std::rc::Rc;
+
+// How many times in a row a recursive monomorphization of the same
+// `fn_id` may instantiate a strictly larger type before it's treated as
+// an infinite expansion.
+static MONO_GROWTH_LIMIT: uint = 4;
 
 pub fn monomorphic_fn(ccx: &CrateContext,
                       fn_id: ast::DefId,
@@ -52,20 +61,32 @@ pub fn monomorphic_fn(ccx: &CrateContext,
 
     let _icx = push_ctxt("monomorphic_fn");
 
+    // Fold unused type parameters down to a canonical substitute before
+    // they become part of the cache key (polymorphization).
+    let used_tps = used_ty_params(ccx, fn_id, real_substs.tps.len());
+    let self_ty_offset = if real_substs.self_ty.is_some() { 1 } else { 0 };
+    let canonicalize = |i: uint, subst: ty::t| -> ty::t {
+        if i < self_ty_offset || *used_tps.get(i - self_ty_offset) {
+            subst
+        } else {
+            erased_param_ty(ccx)
+        }
+    };
+
     let substs_iter = real_substs.self_ty.iter().chain(real_substs.tps.iter());
     let param_ids: Vec<MonoParamId> = match vtables {
         Some(ref vts) => {
             debug!("make_mono_id vtables={} psubsts={}",
                    vts.repr(ccx.tcx()), real_substs.tps.repr(ccx.tcx()));
             let vts_iter = self_vtables.iter().chain(vts.iter());
-            vts_iter.zip(substs_iter).map(|(vtable, subst)| MonoParamId {
-                subst: *subst,
+            vts_iter.zip(substs_iter).enumerate().map(|(i, (vtable, subst))| MonoParamId {
+                subst: canonicalize(i, *subst),
                 // Do we really need the vtables to be hashed? Isn't the type enough?
                 vtables: vtable.iter().map(|vt| make_vtable_id(ccx, vt)).collect()
             }).collect()
         }
-        None => substs_iter.map(|subst| MonoParamId {
-            subst: *subst,
+        None => substs_iter.enumerate().map(|(i, subst)| MonoParamId {
+            subst: canonicalize(i, *subst),
             vtables: Vec::new()
         }).collect()
     };
@@ -181,13 +202,37 @@ pub fn monomorphic_fn(ccx: &CrateContext,
             Some(&d) => d, None => 0
         };
 
-        // Random cut-off -- code that needs to instantiate the same function
-        // recursively more than thirty times can probably safely be assumed
-        // to be causing an infinite expansion.
-        if depth > ccx.sess().recursion_limit.get() {
+        // Track the structural size `fn_id` gets recursively instantiated
+        // with instead of cutting off at a fixed depth: a chain that grows
+        // strictly larger a few times in a row can't terminate, while one
+        // that shrinks or holds steady may recurse arbitrarily deep.
+        let size = ty_structural_size(mono_ty);
+        let mut sizes = ccx.monomorphizing_sizes.borrow_mut();
+        let stack = sizes.find_or_insert_with(fn_id, |_| Vec::new());
+
+        // Longest strictly-increasing run of adjacent steps ending at
+        // `size` -- comparing against every past entry instead would flag
+        // a merely oscillating stack like [1, 2, 1, 2] then 3.
+        let mut consecutive_growth = 0u;
+        let mut prev = size;
+        for &s in stack.iter().rev() {
+            if prev > s {
+                consecutive_growth += 1;
+                prev = s;
+            } else {
+                break;
+            }
+        }
+        if consecutive_growth > MONO_GROWTH_LIMIT {
             ccx.sess().span_fatal(ccx.tcx.map.span(fn_id.node),
-                "reached the recursion limit during monomorphization");
+                format!("reached the recursion limit during monomorphization \
+                         of `{}`: the instantiated type `{}` keeps growing \
+                         with each recursive call, so this instantiation \
+                         would never terminate",
+                        ty::item_path_str(ccx.tcx(), fn_id),
+                        mono_ty.repr(ccx.tcx())).as_slice());
         }
+        stack.push(size);
 
         monomorphizing.insert(fn_id, depth + 1);
     }
@@ -203,6 +248,10 @@ pub fn monomorphic_fn(ccx: &CrateContext,
     });
     debug!("monomorphize_fn mangled to {}", s);
 
+    // Kept so the body-dedup pass below can repoint `monomorphized` at the
+    // canonical definition if this turns out to be a duplicate.
+    let dedup_cache_key = hash_id.clone();
+
     // This shouldn't need to option dance.
     let mut hash_id = Some(hash_id);
     let mk_lldecl = || {
@@ -211,6 +260,14 @@ pub fn monomorphic_fn(ccx: &CrateContext,
         lldecl
     };
 
+    // Whether `lldecl` is a definition we just emitted (eligible for the
+    // body-dedup pass below) or a pre-existing declaration we merely
+    // looked up, e.g. a shared simple intrinsic -- aliasing the latter
+    // onto another lookup of the same kind would be aliasing it to
+    // itself, or worse, to an unrelated intrinsic that happens to hash
+    // the same.
+    let mut freshly_built = true;
+
     let lldecl = match map_node {
         ast_map::NodeItem(i) => {
             match *i {
@@ -231,7 +288,10 @@ pub fn monomorphic_fn(ccx: &CrateContext,
         ast_map::NodeForeignItem(i) => {
             let simple = intrinsic::get_simple_intrinsic(ccx, i);
             match simple {
-                Some(decl) => decl,
+                Some(decl) => {
+                    freshly_built = false;
+                    decl
+                }
                 None => {
                     let d = mk_lldecl();
                     intrinsic::trans_intrinsic(ccx, d, i, &psubsts, ref_id);
@@ -305,21 +365,260 @@ pub fn monomorphic_fn(ccx: &CrateContext,
         }
     };
 
+    // Catches duplicates polymorphization above can't, e.g. distinct
+    // substitutions that still generate byte-identical code. Only
+    // applies to definitions we just built; a shared pre-existing
+    // declaration isn't ours to alias.
+    let lldecl = if freshly_built {
+        dedup_mono_body(ccx, dedup_cache_key, lldecl)
+    } else {
+        lldecl
+    };
+
     ccx.monomorphizing.borrow_mut().insert(fn_id, depth);
+    ccx.monomorphizing_sizes.borrow_mut().find_mut(&fn_id).unwrap().pop();
 
     debug!("leaving monomorphic fn {}", ty::item_path_str(ccx.tcx(), fn_id));
     (lldecl, false)
 }
 
+// Polymorphization: figures out, per `fn_id`, which type parameters the
+// body actually depends on for codegen, so parameters that are merely
+// threaded through don't force a separate monomorphization each.
+
+/// Canonical stand-in for a type parameter proven unused; only ever used
+/// to build `hash_id`, never passed to `ty::subst` for real codegen.
+fn erased_param_ty(ccx: &CrateContext) -> ty::t {
+    ty::mk_nil(ccx.tcx())
+}
+
+/// Per-parameter "does the body of `fn_id` genuinely use this" bits,
+/// cached on the `CrateContext`. Conservative: anything the analysis
+/// can't account for is left marked used.
+fn used_ty_params(ccx: &CrateContext, fn_id: ast::DefId, num_tps: uint) -> Rc<Vec<bool>> {
+    if let Some(cached) = ccx.use_params_cache.borrow().find(&fn_id) {
+        return cached.clone();
+    }
+
+    // Seed with "everything used" before recursing so a cycle in the call
+    // graph terminates instead of looping; overwritten with the real
+    // result below.
+    ccx.use_params_cache.borrow_mut().insert(fn_id, Rc::new(Vec::from_elem(num_tps, true)));
+
+    let used = Rc::new(if fn_id.krate == ast::LOCAL_CRATE {
+        compute_used_ty_params(ccx, fn_id, num_tps)
+    } else {
+        // No local body to inspect for an upstream-crate function; don't
+        // claim more than we can prove.
+        Vec::from_elem(num_tps, true)
+    });
+
+    ccx.use_params_cache.borrow_mut().insert(fn_id, used.clone());
+    used
+}
+
+fn compute_used_ty_params(ccx: &CrateContext, fn_id: ast::DefId, num_tps: uint) -> Vec<bool> {
+    let body = match ccx.tcx.map.find(fn_id.node) {
+        Some(ast_map::NodeItem(item)) => {
+            match item.node {
+                ast::ItemFn(_, _, _, _, body) => body,
+                _ => return Vec::from_elem(num_tps, true),
+            }
+        }
+        Some(ast_map::NodeMethod(m)) => m.body,
+        Some(ast_map::NodeTraitMethod(method)) => {
+            match *method {
+                ast::Provided(m) => m.body,
+                ast::Required(_) => return Vec::from_elem(num_tps, true),
+            }
+        }
+        // Struct/enum constructors, foreign items, etc. have no body whose
+        // uses we can inspect.
+        _ => return Vec::from_elem(num_tps, true),
+    };
+
+    let owners = generic_scope_owners(ccx, fn_id);
+
+    let mut visitor = TyParamUseVisitor {
+        ccx: ccx,
+        owners: owners.clone(),
+        used: Vec::from_elem(num_tps, false),
+    };
+
+    // The signature counts too -- a parameter only mentioned in an arg or
+    // return type still forces a distinct `lldecl`. Occurrences behind a
+    // pointer or reference don't, since those are pointer-sized no matter
+    // what they point to.
+    let fn_ty = ty::lookup_item_type(ccx.tcx(), fn_id).ty;
+    match ty::get(fn_ty).sty {
+        ty::ty_bare_fn(ref f) => {
+            for arg_ty in f.sig.inputs.iter() {
+                mark_value_params_in_ty(owners.as_slice(), *arg_ty, &mut visitor.used);
+            }
+            mark_value_params_in_ty(owners.as_slice(), f.sig.output, &mut visitor.used);
+        }
+        ty::ty_closure(ref f) => {
+            for arg_ty in f.sig.inputs.iter() {
+                mark_value_params_in_ty(owners.as_slice(), *arg_ty, &mut visitor.used);
+            }
+            mark_value_params_in_ty(owners.as_slice(), f.sig.output, &mut visitor.used);
+        }
+        _ => {}
+    }
+
+    visit::walk_block(&mut visitor, body, ());
+    visitor.used
+}
+
+// `real_substs.tps` is the combined list of the item's own type
+// parameters plus, for a method, its enclosing impl/trait's -- so a type
+// parameter's `def_id` may point at either `fn_id` or that enclosing
+// item rather than always at `fn_id` itself.
+fn generic_scope_owners(ccx: &CrateContext, fn_id: ast::DefId) -> Vec<ast::DefId> {
+    let mut owners = vec![fn_id];
+    if fn_id.krate == ast::LOCAL_CRATE {
+        owners.push(local_def(ccx.tcx.map.get_parent(fn_id.node)));
+    }
+    owners
+}
+
+/// Marks which of `fn_id`'s type parameters (whether declared on `fn_id`
+/// itself or on its enclosing impl/trait, see `generic_scope_owners`) show
+/// up in a codegen-relevant position in the body: as an expression's type,
+/// or forwarded to a callee that itself uses the corresponding parameter.
+struct TyParamUseVisitor<'a> {
+    ccx: &'a CrateContext,
+    owners: Vec<ast::DefId>,
+    used: Vec<bool>,
+}
+
+impl<'a> Visitor<()> for TyParamUseVisitor<'a> {
+    fn visit_expr(&mut self, ex: &ast::Expr, _: ()) {
+        if let Some(ex_ty) = ccx_node_type(self.ccx, ex.id) {
+            mark_params_in_ty(self.owners.as_slice(), ex_ty, &mut self.used);
+        }
+
+        // Propagation over the call graph: `fn_id` uses a parameter if it
+        // forwards it to a callee that uses its corresponding parameter.
+        // A callee whose own analysis is still in progress (a cycle in the
+        // call graph) counts as using every parameter; the result is not
+        // iterated any further. Only plain calls are resolved this way;
+        // method calls fall back to the node-type check above.
+        let callee_def = match ex.node {
+            ast::ExprCall(callee, _) =>
+                self.ccx.tcx().def_map.borrow().find_copy(&callee.id)
+                    .map(|d| ast_util::def_id_of_def(d)),
+            _ => None,
+        };
+        if let Some(callee_def) = callee_def {
+            let call_tps = self.ccx.tcx().node_type_substs.borrow().find_copy(&ex.id);
+            if let Some(call_tps) = call_tps {
+                let callee_used = used_ty_params(self.ccx, callee_def, call_tps.len());
+                for (&is_used, subst) in callee_used.iter().zip(call_tps.iter()) {
+                    if is_used {
+                        mark_params_in_ty(self.owners.as_slice(), *subst, &mut self.used);
+                    }
+                }
+            }
+        }
+
+        visit::walk_expr(self, ex, ());
+    }
+}
+
+// Looks up the type the typechecker assigned to node `id`, if any.
+fn ccx_node_type(ccx: &CrateContext, id: ast::NodeId) -> Option<ty::t> {
+    ccx.tcx().node_types.borrow().find_copy(&(id as uint))
+}
+
+// Number of type constructors `ty` is built out of, as a cheap proxy for
+// its "size" (`Wrapper<Wrapper<u8>>` > `Wrapper<u8>` > `u8`).
+fn ty_structural_size(ty: ty::t) -> uint {
+    let mut count = 0u;
+    ty::walk_ty(ty, |_| count += 1);
+    count
+}
+
+/// Marks every type parameter belonging to one of `owners` that occurs in
+/// `ty`, anywhere in the tree.
+fn mark_params_in_ty(owners: &[ast::DefId], ty: ty::t, used: &mut Vec<bool>) {
+    ty::walk_ty(ty, |t| {
+        match ty::get(t).sty {
+            ty::ty_param(ref p) if p.idx < used.len() && owners.contains(&p.def_id) => {
+                *used.get_mut(p.idx) = true;
+            }
+            _ => {}
+        }
+    });
+}
+
+/// Like `mark_params_in_ty`, but only for a type appearing by value: a
+/// parameter that only shows up behind a pointer, reference, or owned/
+/// managed box doesn't affect the fixed, pointer-sized representation of
+/// that position, so it isn't marked just because it's nested there.
+fn mark_value_params_in_ty(owners: &[ast::DefId], ty: ty::t, used: &mut Vec<bool>) {
+    match ty::get(ty).sty {
+        ty::ty_param(ref p) if p.idx < used.len() && owners.contains(&p.def_id) => {
+            *used.get_mut(p.idx) = true;
+        }
+        ty::ty_ptr(..) | ty::ty_rptr(..) | ty::ty_box(..) | ty::ty_uniq(..) => {}
+        ty::ty_tup(ref ts) => {
+            for t in ts.iter() {
+                mark_value_params_in_ty(owners, *t, used);
+            }
+        }
+        ty::ty_vec(ref mt, _) => mark_value_params_in_ty(owners, mt.ty, used),
+        ty::ty_struct(_, ref substs) | ty::ty_enum(_, ref substs) => {
+            for t in substs.tps.iter() {
+                mark_value_params_in_ty(owners, *t, used);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Looks for an earlier monomorphization with the same structural hash
+/// (see `structural_hash`) and LLVM function type as `lldecl`, aliasing
+/// onto it and repointing `cache_key` in `monomorphized` on a hit.
+fn dedup_mono_body(ccx: &CrateContext, cache_key: MonoId, lldecl: ValueRef) -> ValueRef {
+    let body_hash = structural_hash(lldecl);
+    let lldecl_ty = val_ty(lldecl);
+
+    // Same hash doesn't mean same function unless the types match too.
+    let canonical = ccx.mono_body_dedup.borrow().find(&body_hash).and_then(|candidates| {
+        candidates.iter().find(|&&v| val_ty(v) == lldecl_ty).map(|&v| v)
+    });
+
+    let canonical = match canonical {
+        Some(existing) => existing,
+        None => {
+            ccx.mono_body_dedup.borrow_mut().find_or_insert_with(body_hash, |_| Vec::new())
+                .push(lldecl);
+            return lldecl;
+        }
+    };
+
+    debug!("monomorphize: {} is structurally identical to an earlier \
+            monomorphization; aliasing instead of duplicating",
+           cache_key.def);
+
+    // Alias rather than RAUW-and-delete: `lldecl`'s mangled name still
+    // needs to resolve for anyone linking against it directly.
+    base::create_fn_alias(ccx, lldecl, canonical);
+    ccx.monomorphized.borrow_mut().insert(cache_key, canonical);
+    ccx.stats.n_mono_dedups.set(ccx.stats.n_mono_dedups.get() + 1);
+    canonical
+}
+
 // Used to identify cached monomorphized functions and vtables
-#[deriving(Eq, TotalEq, Hash)]
+#[deriving(Clone, Eq, TotalEq, Hash)]
 pub struct MonoParamId {
     pub subst: ty::t,
     // Do we really need the vtables to be hashed? Isn't the type enough?
     pub vtables: Vec<MonoId>
 }
 
-#[deriving(Eq, TotalEq, Hash)]
+#[deriving(Clone, Eq, TotalEq, Hash)]
 pub struct MonoId {
     pub def: ast::DefId,
     pub params: Vec<MonoParamId>
@@ -0,0 +1,25 @@
+// Copyright 2012 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Two monomorphizations whose substitutions differ but whose generated
+// bodies are byte-identical (same-layout integer parameters here, which
+// polymorphization can't collapse since the parameter shows up by value
+// in the signature) should be deduplicated into a single definition,
+// with the later one aliased onto the first.
+
+fn same_layout<T>(x: T) -> T {
+    x
+}
+
+pub fn main() {
+    assert_eq!(same_layout::<u32> as uint, same_layout::<i32> as uint);
+    assert_eq!(same_layout(1u32), 1u32);
+    assert_eq!(same_layout(1i32), 1i32);
+}
@@ -0,0 +1,27 @@
+// Copyright 2012 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Instantiations that differ only in a type parameter the body never
+// inspects should polymorphize down to a single monomorphization, so two
+// differently-typed calls resolve to the same function address.
+
+fn by_ptr<T>(x: *const T) -> uint {
+    x as uint
+}
+
+pub fn main() {
+    let a: uint = 0;
+    let b: u64 = 0;
+
+    assert_eq!(by_ptr::<uint> as uint, by_ptr::<u64> as uint);
+
+    by_ptr(&a);
+    by_ptr(&b);
+}